@@ -3,10 +3,121 @@ use crate::blocks::block::BlockType;
 use crate::utils;
 use anyhow::{anyhow, Result};
 use async_fs::File;
+use async_std::channel;
+use async_std::task::JoinHandle;
+use async_trait::async_trait;
 use futures::prelude::*;
+use regex::Regex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Writes `contents` to `path` crash-safely: write to a temp file in the
+/// same directory, flush, then rename over `path`. The rename is atomic, so
+/// a reader never observes a partially-written file.
+async fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let write_result: Result<()> = async {
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    if write_result.is_err() {
+        let _ = async_std::fs::remove_file(&tmp_path).await;
+        write_result?;
+    }
+    async_std::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Implemented by a versioned on-disk struct to describe how it is produced
+/// from the version that immediately precedes it. `load_versioned` walks a
+/// chain of these, one hop at a time, from whatever version was found on
+/// disk up to `VERSION`. `Previous` is itself `Migrate` so the chain can have
+/// more than one hop; the oldest version in a chain is its own `Previous`
+/// (an identity `upgrade`), which `load_versioned` uses to detect the bottom.
+trait Migrate: Sized + DeserializeOwned {
+    type Previous: Migrate;
+    const VERSION: u32;
+
+    fn upgrade(prev: Self::Previous) -> Self;
+}
+
+/// Reads a versioned on-disk document wrapped as `{ "version": u32, "data": ... }`.
+/// Documents written before this envelope existed are bare JSON objects with
+/// no `version`/`data` keys; `read_versioned` treats those as version `0`.
+fn read_versioned(raw: &str) -> Result<(u32, Value)> {
+    let value: Value = serde_json::from_str(raw)?;
+    match value.as_object() {
+        Some(map) if map.contains_key("version") && map.contains_key("data") => {
+            let version = map["version"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("invalid `version` field in versioned document"))?
+                as u32;
+            Ok((version, map["data"].clone()))
+        }
+        _ => Ok((0, value)),
+    }
+}
+
+fn write_versioned<T: Serialize>(version: u32, data: &T) -> Result<String> {
+    Ok(serde_json::to_string(&serde_json::json!({
+        "version": version,
+        "data": data,
+    }))?)
+}
+
+/// Deserializes `data` (found at `version` in an envelope produced by
+/// `read_versioned`) into `T`, migrating forward one hop at a time,
+/// recursing through `T::Previous` until `version` is reached.
+fn load_versioned<T: Migrate>(version: u32, data: Value) -> Result<T> {
+    if version == T::VERSION {
+        return Ok(serde_json::from_value(data)?);
+    }
+    if version > T::VERSION {
+        Err(anyhow!(
+            "document version {} is newer than the version {} supported by this binary",
+            version,
+            T::VERSION
+        ))?;
+    }
+    if T::VERSION == <T::Previous as Migrate>::VERSION {
+        Err(anyhow!(
+            "document version {} predates the oldest version ({}) this binary can migrate from",
+            version,
+            T::VERSION
+        ))?;
+    }
+    let prev: T::Previous = load_versioned(version, data)?;
+    Ok(T::upgrade(prev))
+}
+
+/// Schema written by binaries prior to the introduction of the `{ version,
+/// data }` envelope. Frozen in time; do not change even if `RunConfig`
+/// changes shape again (add a `RunConfigV1` and migrate from there instead).
+#[derive(Deserialize)]
+struct RunConfigV0 {
+    start_time: u64,
+    app_hash: String,
+    blocks: HashMap<String, Value>,
+}
+
+/// `RunConfigV0` is the oldest version in the chain: it is its own
+/// `Previous`, with an identity `upgrade`, so `load_versioned` can detect the
+/// bottom and stop recursing.
+impl Migrate for RunConfigV0 {
+    type Previous = RunConfigV0;
+    const VERSION: u32 = 0;
+
+    fn upgrade(prev: RunConfigV0) -> Self {
+        prev
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct RunConfig {
@@ -15,33 +126,114 @@ pub struct RunConfig {
     pub blocks: HashMap<String, Value>,
 }
 
+impl Migrate for RunConfig {
+    type Previous = RunConfigV0;
+    const VERSION: u32 = 1;
+
+    fn upgrade(prev: RunConfigV0) -> Self {
+        RunConfig {
+            start_time: prev.start_time,
+            app_hash: prev.app_hash,
+            blocks: prev.blocks,
+        }
+    }
+}
+
 impl RunConfig {
     pub fn config_for_block(&self, name: &str) -> Option<&Value> {
         self.blocks.get(name)
     }
 
+    fn parse(raw: &str) -> Result<Self> {
+        let (version, data) = read_versioned(raw)?;
+        load_versioned(version, data)
+    }
+
+    /// Convenience wrapper over `FilesystemStore`, the default backend, kept
+    /// for call sites that don't need to choose a `RunStore`.
     pub async fn load(run_id: &str) -> Result<Self> {
-        let root_path = utils::init_check().await?;
-        let runs_dir = root_path.join(".runs");
+        FilesystemStore::init().await?.load_config(run_id).await
+    }
+}
 
-        assert!(runs_dir.is_dir().await);
-        let run_dir = runs_dir.join(run_id);
+/// Schema of a per-block `N.json` trace file prior to the `{ version, data
+/// }` envelope. Frozen in time, same rationale as `RunConfigV0`.
+#[derive(Deserialize)]
+struct BlockExecutionsV0(Vec<BlockExecution>);
 
-        if !run_dir.exists().await {
-            Err(anyhow!("Run `{}` does not exist", run_id))?;
-        }
+/// Same rationale as `RunConfigV0::upgrade`: the oldest version is its own
+/// `Previous`, terminating the recursion in `load_versioned`.
+impl Migrate for BlockExecutionsV0 {
+    type Previous = BlockExecutionsV0;
+    const VERSION: u32 = 0;
 
-        let config_path = run_dir.join("config.json");
+    fn upgrade(prev: BlockExecutionsV0) -> Self {
+        prev
+    }
+}
 
-        let config_data = async_std::fs::read_to_string(config_path).await?;
-        let config: RunConfig = serde_json::from_str(&config_data)?;
+impl Migrate for Vec<BlockExecution> {
+    type Previous = BlockExecutionsV0;
+    const VERSION: u32 = 1;
 
-        Ok(config)
+    fn upgrade(prev: BlockExecutionsV0) -> Self {
+        prev.0
     }
 }
 
+fn parse_block_executions(raw: &str) -> Result<Vec<BlockExecution>> {
+    let (version, data) = read_versioned(raw)?;
+    load_versioned(version, data)
+}
+
+/// A queued write for `Run`'s background flush worker: the full set of
+/// inputs for one block, to be written atomically as that block's `N.json`
+/// files under `run_dir`.
+struct PersistTask {
+    run_dir: PathBuf,
+    block_idx: usize,
+    block_type: BlockType,
+    name: String,
+    block_execution: Vec<Vec<BlockExecution>>,
+}
+
+async fn run_persist_task(task: PersistTask) -> Result<()> {
+    let block_dir = task.run_dir.join(block_dir_name(task.block_idx));
+    async_std::fs::create_dir_all(&block_dir).await?;
+    write_block_manifest(&block_dir, &task.block_type, &task.name).await?;
+
+    for (input_idx, executions) in task.block_execution.iter().enumerate() {
+        let path = block_dir.join(format!("{}.json", input_idx));
+        write_atomic(
+            &path,
+            &write_versioned(<Vec<BlockExecution> as Migrate>::VERSION, executions)?,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Keeps the first `Err` seen across a sequence of persist results, leaving
+/// `first_err` untouched once it's set so a later failure doesn't mask an
+/// earlier one. Used by the `Run::create` flush worker, which must keep
+/// draining the queue after a failure rather than stop at the first one.
+fn record_first_error(first_err: &mut Option<anyhow::Error>, result: Result<()>) {
+    if let Err(err) = result {
+        first_err.get_or_insert(err);
+    }
+}
+
+/// Handle to the background task that writes blocks queued via
+/// `Run::persist_block`. Dropping the sender (done by `Run::flush`) lets the
+/// worker drain the queue and exit.
+struct PersistWorker {
+    run_dir: PathBuf,
+    sender: channel::Sender<PersistTask>,
+    worker: JoinHandle<Result<()>>,
+}
+
 /// Execution represents the full execution of an app on input data.
-#[derive(PartialEq)]
 pub struct Run {
     run_id: String,
     config: RunConfig,
@@ -55,6 +247,15 @@ pub struct Run {
     // TODO(spolu): note that there is a lot of repetition here in particular through the env
     // variables, will need to be revisited but that's a fair enough starting point.
     pub traces: Vec<((BlockType, String), Vec<Vec<BlockExecution>>)>,
+    // Set for runs created with `create`; lets `persist_block` stream each
+    // block to disk as it completes instead of waiting for `store`.
+    persist: Option<PersistWorker>,
+}
+
+impl PartialEq for Run {
+    fn eq(&self, other: &Self) -> bool {
+        self.run_id == other.run_id && self.config == other.config && self.traces == other.traces
+    }
 }
 
 impl Run {
@@ -63,99 +264,1410 @@ impl Run {
             run_id: utils::new_id(),
             config,
             traces: vec![],
+            persist: None,
         }
     }
 
+    /// Like `new`, but eagerly creates the run's directory and writes
+    /// `config.json`, and spawns a background worker so that blocks can be
+    /// streamed to disk via `persist_block` as they complete. A run created
+    /// this way survives being interrupted mid-execution: `Run::load` will
+    /// reconstruct `traces` from whatever block directories were written
+    /// before the crash.
+    ///
+    /// Incremental persistence only exists for `FilesystemStore`: it streams
+    /// blocks straight to a `.runs`-style directory, which `SqlStore` never
+    /// reads. If `DUST_RUN_STORE_URL` is set, `open_store` would hand
+    /// `store()` a `SqlStore`, so creating a run this way would silently
+    /// drop every block persisted before the final `store()` call on a
+    /// crash — this fails loudly instead. Use `Run::new` and call `store`
+    /// once the run completes when running against `SqlStore`.
+    pub async fn create(config: RunConfig) -> Result<Self> {
+        if let Some(database_url) = sql_store_url() {
+            return Err(anyhow!(
+                "incremental persistence (`Run::create`) is not supported with the SqlStore \
+                 backend selected by DUST_RUN_STORE_URL=`{}`; use `Run::new` and call `store` \
+                 once the run completes instead",
+                database_url
+            ));
+        }
+
+        let paths = RunPaths::resolve().await?;
+        let runs_dir = paths.writable().await?;
+
+        let run_id = utils::new_id();
+        let run_dir = runs_dir.join(&run_id);
+
+        utils::action(&format!("Creating directory {}", run_dir.display()));
+        async_std::fs::create_dir_all(&run_dir).await?;
+
+        utils::action(&format!(
+            "Writing run config in {}",
+            run_dir.join("config.json").display()
+        ));
+        write_atomic(
+            &run_dir.join("config.json"),
+            &write_versioned(RunConfig::VERSION, &config)?,
+        )
+        .await?;
+
+        let (sender, receiver) = channel::unbounded::<PersistTask>();
+        let worker = async_std::task::spawn(async move {
+            // Keep draining the queue even after a failure, so one bad
+            // block doesn't strand the rest; `flush` surfaces the first
+            // error it hit (if any) once the queue is empty.
+            let mut first_err = None;
+            while let Ok(task) = receiver.recv().await {
+                let result = run_persist_task(task).await;
+                if let Err(err) = &result {
+                    utils::error(&format!("failed to persist run block: {}", err));
+                }
+                record_first_error(&mut first_err, result);
+            }
+            match first_err {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        });
+
+        Ok(Self {
+            run_id,
+            config,
+            traces: vec![],
+            persist: Some(PersistWorker {
+                run_dir,
+                sender,
+                worker,
+            }),
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
     pub fn config(&self) -> &RunConfig {
         &self.config
     }
 
-    pub async fn store(&self) -> Result<()> {
+    /// Queues `block_execution` for `block_idx`/`key` to be atomically
+    /// written to disk (temp file + rename) by the background worker
+    /// started in `create`; returns as soon as the write is queued, not
+    /// once it lands on disk. Only valid on a run created with `create`.
+    pub async fn persist_block(
+        &self,
+        block_idx: usize,
+        key: &(BlockType, String),
+        block_execution: Vec<Vec<BlockExecution>>,
+    ) -> Result<()> {
+        let persist = self.persist.as_ref().ok_or_else(|| {
+            anyhow!(
+                "run `{}` was not created with incremental persistence",
+                self.run_id
+            )
+        })?;
+
+        persist
+            .sender
+            .send(PersistTask {
+                run_dir: persist.run_dir.clone(),
+                block_idx,
+                block_type: key.0.clone(),
+                name: key.1.clone(),
+                block_execution,
+            })
+            .await
+            .map_err(|_| anyhow!("run's flush worker has shut down"))
+    }
+
+    /// Waits for every block queued via `persist_block` to be written to
+    /// disk. A no-op on a run without incremental persistence.
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Some(persist) = self.persist.take() {
+            drop(persist.sender);
+            persist.worker.await?;
+        }
+        Ok(())
+    }
+
+    /// Stores via `open_store`, the backend selected by `DUST_RUN_STORE_URL`
+    /// (or `FilesystemStore` by default). Flushes any blocks queued via
+    /// `persist_block` first so this write is the last one to land.
+    pub async fn store(&mut self) -> Result<()> {
+        self.flush().await?;
+        open_store().await?.store_run(self).await
+    }
+
+    pub async fn load(run_id: &str) -> Result<Self> {
+        let store = open_store().await?;
+        let config = store.load_config(run_id).await?;
+        let traces = store.load_traces(run_id).await?;
+
+        Ok(Run {
+            run_id: run_id.to_string(),
+            config,
+            traces,
+            persist: None,
+        })
+    }
+}
+
+/// Criteria for `RunStore::list_runs`. All set fields are conjunctive (a run
+/// must match every one of them to be returned); `limit` caps the number of
+/// results after filtering, applied in `start_time` descending order.
+#[derive(Default, Clone)]
+pub struct RunFilter {
+    pub app_hash: Option<String>,
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl RunFilter {
+    fn matches(&self, config: &RunConfig) -> bool {
+        if let Some(app_hash) = &self.app_hash {
+            if &config.app_hash != app_hash {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if config.start_time < since {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Backend for persisting and querying runs. `FilesystemStore` is the
+/// default, backed by the `.runs` directory tree; `SqlStore` keeps run and
+/// block metadata in indexed tables so `list_runs` can filter/sort without
+/// reading every run's `config.json` off disk.
+#[async_trait]
+pub trait RunStore: Send + Sync {
+    async fn store_run(&self, run: &Run) -> Result<()>;
+    async fn load_config(&self, run_id: &str) -> Result<RunConfig>;
+    async fn load_traces(
+        &self,
+        run_id: &str,
+    ) -> Result<Vec<((BlockType, String), Vec<Vec<BlockExecution>>)>>;
+    async fn list_runs(&self, filter: &RunFilter) -> Result<Vec<(String, RunConfig)>>;
+}
+
+/// A block's directory is just its index; `block_type`/`name` are never
+/// encoded positionally into the directory name (a `BlockType`'s `Display`
+/// form or a block's `name` could itself contain `-`/`_`, which would make
+/// any delimited encoding ambiguous to split back apart). They're recorded
+/// structurally instead, in `block.json` alongside the per-input files.
+fn block_dir_name(block_idx: usize) -> String {
+    block_idx.to_string()
+}
+
+/// Parses the pre-`block.json` `{idx}-{block_type}_{name}` directory name
+/// written by binaries prior to this change, so runs already on disk under
+/// that format keep loading. Read-only: `FilesystemStore` never writes this
+/// format again, it's purely a fallback for `load_traces`.
+fn parse_legacy_block_dir_name(dir_name: &str) -> Result<(usize, BlockType, String)> {
+    let (idx_part, rest) = dir_name
+        .split_once('-')
+        .ok_or_else(|| anyhow!("not a legacy block directory name `{}`", dir_name))?;
+    let (type_part, name_part) = rest
+        .split_once('_')
+        .ok_or_else(|| anyhow!("not a legacy block directory name `{}`", dir_name))?;
+
+    let block_idx = idx_part
+        .parse::<usize>()
+        .map_err(|_| anyhow!("not a legacy block directory name `{}`", dir_name))?;
+    let block_type = BlockType::from_str(type_part)
+        .map_err(|_| anyhow!("invalid block type `{}` in `{}`", type_part, dir_name))?;
+
+    Ok((block_idx, block_type, name_part.to_string()))
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockManifest {
+    block_type: String,
+    name: String,
+}
+
+async fn write_block_manifest(block_dir: &Path, block_type: &BlockType, name: &str) -> Result<()> {
+    write_atomic(
+        &block_dir.join("block.json"),
+        &serde_json::to_string(&BlockManifest {
+            block_type: block_type.to_string(),
+            name: name.to_string(),
+        })?,
+    )
+    .await
+}
+
+async fn read_block_manifest(block_dir: &Path) -> Result<(BlockType, String)> {
+    let raw = async_std::fs::read_to_string(block_dir.join("block.json")).await?;
+    let manifest: BlockManifest = serde_json::from_str(&raw)?;
+    let block_type = BlockType::from_str(&manifest.block_type)
+        .map_err(|_| anyhow!("invalid block type `{}`", manifest.block_type))?;
+    Ok((block_type, manifest.name))
+}
+
+/// Ordered list of `.runs`-style directories runs are looked up in: writes
+/// always go to the first location that's writable (normally the
+/// project-local `.runs`), while reads and `list_runs` merge across every
+/// location that exists, first match wins on a duplicate `run_id`. This
+/// lets a deployment share a read-only archive of runs (resolved via XDG
+/// base directories, or an explicit override) alongside its own ephemeral
+/// `.runs`.
+pub struct RunPaths {
+    locations: Vec<PathBuf>,
+}
+
+impl RunPaths {
+    /// Project-local `.runs` first, then the XDG data directory for `dust`
+    /// (`$XDG_DATA_HOME/dust/runs` or platform equivalent), then each entry
+    /// of `DUST_RUNS_PATH` (a `:`-separated list, like `$PATH`), in that
+    /// order.
+    pub async fn resolve() -> Result<Self> {
         let root_path = utils::init_check().await?;
-        let runs_dir = root_path.join(".runs");
+        let mut locations = vec![root_path.join(".runs")];
+
+        if let Some(dirs) = directories::ProjectDirs::from("com", "dust", "dust") {
+            locations.push(dirs.data_dir().join("runs"));
+        }
+
+        if let Some(extra) = std::env::var_os("DUST_RUNS_PATH") {
+            locations.extend(std::env::split_paths(&extra));
+        }
+
+        Ok(Self { locations })
+    }
+
+    pub fn from_locations(locations: Vec<PathBuf>) -> Self {
+        Self { locations }
+    }
+
+    /// The location new runs are written to: the first location, in
+    /// priority order, that already exists or can be created. An existing
+    /// directory is not probed for write permission.
+    async fn writable(&self) -> Result<&PathBuf> {
+        for location in &self.locations {
+            if async_std::fs::create_dir_all(location).await.is_ok() {
+                return Ok(location);
+            }
+        }
+        Err(anyhow!(
+            "no writable run storage location found (searched {})",
+            self.locations
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+
+    /// Every location that currently exists, in priority order.
+    async fn readable(&self) -> Vec<&PathBuf> {
+        let mut found = vec![];
+        for location in &self.locations {
+            if location.is_dir().await {
+                found.push(location);
+            }
+        }
+        found
+    }
 
-        assert!(runs_dir.is_dir().await);
-        let run_dir = runs_dir.join(&self.run_id);
-        assert!(!run_dir.exists().await);
+    /// The first location (in priority order) that holds `run_id`.
+    async fn locate(&self, run_id: &str) -> Option<&PathBuf> {
+        for location in &self.locations {
+            if location.join(run_id).is_dir().await {
+                return Some(location);
+            }
+        }
+        None
+    }
+}
+
+pub struct FilesystemStore {
+    paths: RunPaths,
+}
+
+impl FilesystemStore {
+    pub async fn init() -> Result<Self> {
+        Ok(Self {
+            paths: RunPaths::resolve().await?,
+        })
+    }
+
+    pub fn with_paths(paths: RunPaths) -> Self {
+        Self { paths }
+    }
+
+    /// Resolves `run_id` to its on-disk directory via `RunPaths`, the same
+    /// search-path resolution `load_config`/`load_traces` use. Exposed so
+    /// callers that need a run's location for a file `RunStore` doesn't
+    /// know about (e.g. `expectations.json`) stay in sync with it.
+    async fn run_dir(&self, run_id: &str) -> Result<PathBuf> {
+        let runs_dir = self
+            .paths
+            .locate(run_id)
+            .await
+            .ok_or_else(|| anyhow!("Run `{}` does not exist", run_id))?;
+        Ok(runs_dir.join(run_id))
+    }
+
+    /// Like `list_runs`, but annotates each run with the location it was
+    /// found in, for `cmd_list` to report which search-path entry served
+    /// it. Runs are deduplicated by `run_id`, first location wins.
+    async fn list_runs_annotated(&self, filter: &RunFilter) -> Result<Vec<(String, RunConfig, PathBuf)>> {
+        let mut runs: Vec<(String, RunConfig, PathBuf)> = vec![];
+        let mut seen = std::collections::HashSet::new();
+
+        for runs_dir in self.paths.readable().await {
+            let mut entries = async_std::fs::read_dir(runs_dir).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let path = entry.path();
+                if !path.is_dir().await {
+                    continue;
+                }
+                let run_id = path.file_name().unwrap().to_str().unwrap().to_string();
+                if !seen.insert(run_id.clone()) {
+                    continue;
+                }
+                let config = self.load_config(&run_id).await?;
+                if filter.matches(&config) {
+                    runs.push((run_id, config, runs_dir.clone()));
+                }
+            }
+        }
+
+        runs.sort_by(|a, b| b.1.start_time.cmp(&a.1.start_time));
+        if let Some(limit) = filter.limit {
+            runs.truncate(limit);
+        }
+
+        Ok(runs)
+    }
+}
+
+#[async_trait]
+impl RunStore for FilesystemStore {
+    async fn store_run(&self, run: &Run) -> Result<()> {
+        let runs_dir = self.paths.writable().await?;
+        // `run_dir` may already exist (and be partially populated) if `run`
+        // was created with `Run::create` and some blocks were streamed to
+        // disk via `persist_block`; this write is authoritative and
+        // overwrites whatever's there.
+        let run_dir = runs_dir.join(&run.run_id);
 
         utils::action(&format!("Creating directory {}", run_dir.display()));
         async_std::fs::create_dir_all(&run_dir).await?;
 
         let config_path = run_dir.join("config.json");
         utils::action(&format!("Writing run config in {}", config_path.display()));
-        {
-            let mut file = File::create(config_path).await?;
-            file.write_all(serde_json::to_string(&self.config)?.as_bytes())
-                .await?;
-            file.flush().await?;
-        }
+        write_atomic(
+            &config_path,
+            &write_versioned(RunConfig::VERSION, &run.config)?,
+        )
+        .await?;
 
-        for (block_idx, ((block_type, name), block_execution)) in self.traces.iter().enumerate() {
-            let block_dir =
-                run_dir.join(format!("{}-{}_{}", block_idx, block_type.to_string(), name));
+        for (block_idx, ((block_type, name), block_execution)) in run.traces.iter().enumerate() {
+            let block_dir = run_dir.join(block_dir_name(block_idx));
             utils::action(&format!("Creating directory {}", block_dir.display()));
             async_std::fs::create_dir_all(&block_dir).await?;
+            write_block_manifest(&block_dir, block_type, name).await?;
             for (input_idx, executions) in block_execution.iter().enumerate() {
                 let executions_path = block_dir.join(format!("{}.json", input_idx));
-                {
-                    let mut file = File::create(executions_path).await?;
-                    file.write_all(serde_json::to_string(executions)?.as_bytes())
-                        .await?;
-                    file.flush().await?;
-                }
+                write_atomic(
+                    &executions_path,
+                    &write_versioned(<Vec<BlockExecution> as Migrate>::VERSION, executions)?,
+                )
+                .await?;
             }
         }
         utils::done(&format!(
             "Run `{}` for app version `{}` stored",
-            self.run_id, self.config.app_hash
+            run.run_id, run.config.app_hash
         ));
 
         Ok(())
     }
 
-    pub async fn load(run_id: &str) -> Result<Self> {
-        let config = RunConfig::load(run_id).await?;
+    async fn load_config(&self, run_id: &str) -> Result<RunConfig> {
+        let run_dir = self.run_dir(run_id).await?;
 
-        Ok(Run {
-            run_id: run_id.to_string(),
-            config,
-            traces: vec![],
-        })
+        let config_path = run_dir.join("config.json");
+        let config_data = async_std::fs::read_to_string(config_path).await?;
+        RunConfig::parse(&config_data)
+    }
+
+    async fn load_traces(
+        &self,
+        run_id: &str,
+    ) -> Result<Vec<((BlockType, String), Vec<Vec<BlockExecution>>)>> {
+        let run_dir = self.run_dir(run_id).await?;
+
+        let mut blocks: Vec<(usize, (BlockType, String), Vec<Vec<BlockExecution>>)> = vec![];
+
+        let mut entries = async_std::fs::read_dir(&run_dir).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let path = entry.path();
+            if !path.is_dir().await {
+                continue;
+            }
+            let dir_name = match path.file_name().and_then(|s| s.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let (block_idx, block_type, name) = match dir_name.parse::<usize>() {
+                Ok(block_idx) => match read_block_manifest(&path).await {
+                    Ok((block_type, name)) => (block_idx, block_type, name),
+                    // `create_dir_all` and `write_block_manifest` aren't a
+                    // single atomic step in `run_persist_task`; a crash
+                    // between the two leaves a block directory with no
+                    // `block.json`. Skip just that block rather than
+                    // failing reconstruction of the whole run's traces.
+                    Err(err) => {
+                        utils::error(&format!(
+                            "skipping block directory {} (missing or invalid block.json: {})",
+                            path.display(),
+                            err
+                        ));
+                        continue;
+                    }
+                },
+                // Runs written before `block.json` was introduced: fall back
+                // to the old positional `{idx}-{type}_{name}` encoding so
+                // they keep loading instead of silently losing their traces.
+                Err(_) => match parse_legacy_block_dir_name(dir_name) {
+                    Ok(legacy) => legacy,
+                    Err(_) => continue,
+                },
+            };
+
+            let mut executions: Vec<(usize, Vec<BlockExecution>)> = vec![];
+            let mut input_entries = async_std::fs::read_dir(&path).await?;
+            while let Some(input_entry) = input_entries.next().await {
+                let input_entry = input_entry?;
+                let input_path = input_entry.path();
+                let input_idx = match input_path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<usize>().ok())
+                {
+                    Some(idx) => idx,
+                    None => continue,
+                };
+                let raw = async_std::fs::read_to_string(&input_path).await?;
+                executions.push((input_idx, parse_block_executions(&raw)?));
+            }
+            executions.sort_by_key(|(idx, _)| *idx);
+
+            blocks.push((
+                block_idx,
+                (block_type, name),
+                executions.into_iter().map(|(_, e)| e).collect(),
+            ));
+        }
+
+        blocks.sort_by_key(|(idx, _, _)| *idx);
+
+        Ok(blocks.into_iter().map(|(_, key, e)| (key, e)).collect())
+    }
+
+    async fn list_runs(&self, filter: &RunFilter) -> Result<Vec<(String, RunConfig)>> {
+        Ok(self
+            .list_runs_annotated(filter)
+            .await?
+            .into_iter()
+            .map(|(run_id, config, _location)| (run_id, config))
+            .collect())
+    }
+}
+
+/// SQL-backed `RunStore`, suitable for a hosted multi-run deployment: run
+/// metadata and block traces live in indexed tables instead of a directory
+/// tree, so `list_runs` can filter/sort (e.g. "last 10 runs for `app_hash`
+/// X") without deserializing every run on disk. Works against SQLite or
+/// Postgres, whichever `database_url` points at.
+pub struct SqlStore {
+    pool: sqlx::AnyPool,
+}
+
+impl SqlStore {
+    pub async fn init(database_url: &str) -> Result<Self> {
+        sqlx::any::install_default_drivers();
+        // A default `AnyPool` hands out multiple connections; for
+        // `sqlite::memory:` (and any other private in-memory SQLite URL)
+        // each connection gets its own private database, so concurrent
+        // callers can silently miss each other's writes. Pin the pool to a
+        // single connection so every query goes through the same database.
+        let pool = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect(database_url)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS runs ( \
+                 run_id TEXT PRIMARY KEY, \
+                 app_hash TEXT NOT NULL, \
+                 start_time BIGINT NOT NULL, \
+                 config_json TEXT NOT NULL \
+             )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS runs_app_hash ON runs(app_hash)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS runs_start_time ON runs(start_time)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS run_blocks ( \
+                 run_id TEXT NOT NULL, \
+                 block_idx BIGINT NOT NULL, \
+                 block_type TEXT NOT NULL, \
+                 block_name TEXT NOT NULL, \
+                 input_idx BIGINT NOT NULL, \
+                 executions_json TEXT NOT NULL, \
+                 PRIMARY KEY (run_id, block_idx, input_idx) \
+             )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS run_blocks_run_id ON run_blocks(run_id)")
+            .execute(&pool)
+            .await?;
+
+        Ok(Self { pool })
     }
 }
 
+#[async_trait]
+impl RunStore for SqlStore {
+    async fn store_run(&self, run: &Run) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO runs (run_id, app_hash, start_time, config_json) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(run.run_id.clone())
+        .bind(run.config.app_hash.clone())
+        .bind(run.config.start_time as i64)
+        .bind(write_versioned(RunConfig::VERSION, &run.config)?)
+        .execute(&self.pool)
+        .await?;
+
+        for (block_idx, ((block_type, name), block_execution)) in run.traces.iter().enumerate() {
+            for (input_idx, executions) in block_execution.iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO run_blocks \
+                         (run_id, block_idx, block_type, block_name, input_idx, executions_json) \
+                     VALUES ($1, $2, $3, $4, $5, $6)",
+                )
+                .bind(run.run_id.clone())
+                .bind(block_idx as i64)
+                .bind(block_type.to_string())
+                .bind(name.clone())
+                .bind(input_idx as i64)
+                .bind(write_versioned(
+                    <Vec<BlockExecution> as Migrate>::VERSION,
+                    executions,
+                )?)
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load_config(&self, run_id: &str) -> Result<RunConfig> {
+        let row: (String,) = sqlx::query_as("SELECT config_json FROM runs WHERE run_id = $1")
+            .bind(run_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow!("Run `{}` does not exist", run_id))?;
+
+        RunConfig::parse(&row.0)
+    }
+
+    async fn load_traces(
+        &self,
+        run_id: &str,
+    ) -> Result<Vec<((BlockType, String), Vec<Vec<BlockExecution>>)>> {
+        let rows: Vec<(i64, String, String, i64, String)> = sqlx::query_as(
+            "SELECT block_idx, block_type, block_name, input_idx, executions_json \
+             FROM run_blocks WHERE run_id = $1 ORDER BY block_idx, input_idx",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut blocks: Vec<(i64, (BlockType, String), Vec<Vec<BlockExecution>>)> = vec![];
+        for (block_idx, block_type, block_name, _input_idx, executions_json) in rows {
+            let executions = parse_block_executions(&executions_json)?;
+            match blocks.last_mut() {
+                Some((idx, _, inputs)) if *idx == block_idx => inputs.push(executions),
+                _ => {
+                    let block_type = BlockType::from_str(&block_type)
+                        .map_err(|_| anyhow!("invalid block type `{}`", block_type))?;
+                    blocks.push((block_idx, (block_type, block_name), vec![executions]));
+                }
+            }
+        }
+
+        Ok(blocks.into_iter().map(|(_, key, e)| (key, e)).collect())
+    }
+
+    async fn list_runs(&self, filter: &RunFilter) -> Result<Vec<(String, RunConfig)>> {
+        let mut query = String::from(
+            "SELECT run_id, config_json FROM runs WHERE ($1 IS NULL OR app_hash = $1) \
+             AND ($2 IS NULL OR start_time >= $2) ORDER BY start_time DESC",
+        );
+        if filter.limit.is_some() {
+            query.push_str(" LIMIT $3");
+        }
+
+        let mut q = sqlx::query_as::<_, (String, String)>(&query)
+            .bind(filter.app_hash.clone())
+            .bind(filter.since.map(|s| s as i64));
+        if let Some(limit) = filter.limit {
+            q = q.bind(limit as i64);
+        }
+
+        let rows = q.fetch_all(&self.pool).await?;
+        rows.into_iter()
+            .map(|(run_id, config_json)| Ok((run_id, RunConfig::parse(&config_json)?)))
+            .collect()
+    }
+}
+
+/// Selects the `RunStore` backend for call sites that work against
+/// whichever run storage is configured rather than a specific backend:
+/// `SqlStore` if `DUST_RUN_STORE_URL` is set (a `sqlx` connection string for
+/// SQLite or Postgres), else `FilesystemStore` backed by the `.runs` search
+/// path.
+async fn open_store() -> Result<Box<dyn RunStore>> {
+    match sql_store_url() {
+        Some(database_url) => Ok(Box::new(SqlStore::init(&database_url).await?)),
+        None => Ok(Box::new(FilesystemStore::init().await?)),
+    }
+}
+
+/// `DUST_RUN_STORE_URL`, if set: the connection string `open_store` uses to
+/// select `SqlStore` over the default `FilesystemStore`. Factored out so
+/// `Run::create` can check the same selection `open_store` would make,
+/// without actually connecting.
+fn sql_store_url() -> Option<String> {
+    std::env::var("DUST_RUN_STORE_URL").ok()
+}
+
+/// `expectations.json`, stored alongside a run, maps a block name to a map
+/// from JSON pointer path (within that block's output `Value`) to a regex
+/// the stringified value at that path must match. This gives golden-run
+/// regression checks: a block's output is free to change shape as long as
+/// the paths a caller cares about keep matching.
+type Expectations = HashMap<String, HashMap<String, String>>;
+
+async fn load_expectations(run_id: &str) -> Result<Option<Expectations>> {
+    let run_dir = FilesystemStore::init().await?.run_dir(run_id).await?;
+    let expectations_path = run_dir.join("expectations.json");
+
+    if !expectations_path.exists().await {
+        return Ok(None);
+    }
+
+    let raw = async_std::fs::read_to_string(expectations_path).await?;
+    Ok(Some(serde_json::from_str(&raw)?))
+}
+
+/// Stringifies a `Value` for comparison against an expectation's regex:
+/// strings compare by their raw contents, everything else (numbers, bools,
+/// arrays, objects) by its JSON rendering.
+fn stringify_expectation_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Whether `pattern` matches the stringified value found at `path` (a JSON
+/// pointer) within `output`. `Ok(None)` means `path` isn't present in
+/// `output` at all, which `cmd_inspect` reports distinctly from a present
+/// but non-matching value.
+fn expectation_matches(output: &Value, path: &str, pattern: &str) -> Result<Option<bool>> {
+    let actual = match output.pointer(path) {
+        Some(value) => stringify_expectation_value(value),
+        None => return Ok(None),
+    };
+    Ok(Some(Regex::new(pattern)?.is_match(&actual)))
+}
+
+/// Checks a run's block output against `expectations.json`, reporting a
+/// pass/fail per (input, mapped output, expected path) and returning an
+/// error if any expectation fails. Mirrors the fd-to-regex expected-output
+/// matching used in distributed test harnesses, applied to block `Value`s
+/// instead of process output.
 pub async fn cmd_inspect(run_id: &str, block: &str) -> Result<()> {
     let run = Run::load(run_id).await?;
 
-    Ok(())
-}
+    let expectations = load_expectations(run_id)
+        .await?
+        .ok_or_else(|| anyhow!("no `expectations.json` found for run `{}`", run_id))?;
+    let block_expectations = expectations
+        .get(block)
+        .ok_or_else(|| anyhow!("no expectations registered for block `{}`", block))?;
 
-pub async fn cmd_list() -> Result<()> {
-    let root_path = utils::init_check().await?;
-    let runs_dir = root_path.join(".runs");
+    let (_, block_execution) = run
+        .traces
+        .iter()
+        .find(|((_, name), _)| name == block)
+        .ok_or_else(|| anyhow!("block `{}` not found in run `{}`", block, run_id))?;
 
-    let mut entries = async_std::fs::read_dir(runs_dir).await?;
+    let mut failures = 0;
+    for (input_idx, executions) in block_execution.iter().enumerate() {
+        for (map_idx, execution) in executions.iter().enumerate() {
+            let output = match &execution.value {
+                Some(value) => value,
+                None => continue,
+            };
 
-    let mut runs: Vec<(String, RunConfig)> = vec![];
-    while let Some(entry) = entries.next().await {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir().await {
-            let run_id = path.file_name().unwrap().to_str().unwrap();
-            let config = RunConfig::load(run_id).await?;
-            runs.push((run_id.to_string(), config));
+            for (path, pattern) in block_expectations {
+                match expectation_matches(output, path, pattern)? {
+                    None => {
+                        failures += 1;
+                        utils::error(&format!(
+                            "[{}][{}][{}] path `{}` not found in output",
+                            block, input_idx, map_idx, path
+                        ));
+                    }
+                    Some(true) => utils::info(&format!(
+                        "[{}][{}][{}] path `{}` matched `{}`",
+                        block, input_idx, map_idx, path, pattern
+                    )),
+                    Some(false) => {
+                        failures += 1;
+                        let actual =
+                            stringify_expectation_value(output.pointer(path).unwrap());
+                        utils::error(&format!(
+                            "[{}][{}][{}] path `{}` = `{}` does not match `{}`",
+                            block, input_idx, map_idx, path, actual, pattern
+                        ));
+                    }
+                }
+            }
         }
     }
 
-    runs.sort_by(|a, b| b.1.start_time.cmp(&a.1.start_time));
+    if failures > 0 {
+        Err(anyhow!(
+            "{} expectation(s) failed for block `{}` in run `{}`",
+            failures,
+            block,
+            run_id
+        ))?;
+    }
+
+    utils::done(&format!(
+        "All expectations passed for block `{}` in run `{}`",
+        block, run_id
+    ));
 
-    runs.iter().for_each(|(run_id, config)| {
+    Ok(())
+}
+
+/// Lists runs matching `filter`, annotated with which `RunPaths` search-path
+/// entry served each one. Always goes through `FilesystemStore` rather than
+/// `open_store`: the location annotation is specific to the filesystem
+/// search path and has no equivalent in `SqlStore`.
+pub async fn cmd_list(filter: RunFilter) -> Result<()> {
+    let store = FilesystemStore::init().await?;
+    let runs = store.list_runs_annotated(&filter).await?;
+
+    runs.iter().for_each(|(run_id, config, location)| {
         utils::info(&format!(
-            "Run: {} app_hash={} start_time={}",
+            "Run: {} app_hash={} start_time={} location={}",
             run_id,
             config.app_hash,
             utils::utc_date_from(config.start_time),
+            location.display(),
         ));
     });
     Ok(())
+}
+
+/// A leaf-level difference between two output `Value`s, keyed by the JSON
+/// pointer path it was found at.
+#[derive(Debug)]
+enum ValueDiff {
+    Added(Value),
+    Removed(Value),
+    Changed { before: Value, after: Value },
+}
+
+/// Recursively walks `a` and `b`, pushing one `ValueDiff` per added,
+/// removed, or changed leaf (objects are walked key by key; anything else,
+/// including arrays, is compared as a whole so index shifts show up as a
+/// single `Changed` rather than a confusing per-element diff).
+fn diff_values(path: &str, a: &Value, b: &Value, out: &mut Vec<(String, ValueDiff)>) {
+    match (a, b) {
+        (Value::Object(ma), Value::Object(mb)) => {
+            for (k, va) in ma {
+                let child_path = format!("{}/{}", path, k);
+                match mb.get(k) {
+                    Some(vb) => diff_values(&child_path, va, vb, out),
+                    None => out.push((child_path, ValueDiff::Removed(va.clone()))),
+                }
+            }
+            for (k, vb) in mb {
+                if !ma.contains_key(k) {
+                    out.push((format!("{}/{}", path, k), ValueDiff::Added(vb.clone())));
+                }
+            }
+        }
+        (a, b) if a != b => out.push((
+            path.to_string(),
+            ValueDiff::Changed {
+                before: a.clone(),
+                after: b.clone(),
+            },
+        )),
+        _ => {}
+    }
+}
+
+fn position_of(
+    traces: &[((BlockType, String), Vec<Vec<BlockExecution>>)],
+    key: &(BlockType, String),
+) -> Option<usize> {
+    traces.iter().position(|(k, _)| k == key)
+}
+
+/// Block-by-block, input-by-input comparison of two runs of (presumably)
+/// the same app: blocks present in only one run, blocks whose config
+/// differs, and for matching `BlockExecution`s whether the error or the
+/// output changed, with a JSON-aware diff of the output `Value`.
+pub async fn cmd_diff(run_id_a: &str, run_id_b: &str) -> Result<()> {
+    let run_a = Run::load(run_id_a).await?;
+    let run_b = Run::load(run_id_b).await?;
+
+    let mut keys: Vec<(BlockType, String)> =
+        run_a.traces.iter().map(|(key, _)| key.clone()).collect();
+    for (key, _) in run_b.traces.iter() {
+        if !keys.contains(key) {
+            keys.push(key.clone());
+        }
+    }
+
+    for key in &keys {
+        let (block_type, name) = key;
+        let label = format!("{}/{}", block_type.to_string(), name);
+
+        let idx_a = position_of(&run_a.traces, key);
+        let idx_b = position_of(&run_b.traces, key);
+
+        let (idx_a, idx_b) = match (idx_a, idx_b) {
+            (Some(_), None) => {
+                utils::info(&format!("- block `{}` only in `{}`", label, run_id_a));
+                continue;
+            }
+            (None, Some(_)) => {
+                utils::info(&format!("+ block `{}` only in `{}`", label, run_id_b));
+                continue;
+            }
+            (Some(a), Some(b)) => (a, b),
+            (None, None) => unreachable!("key was collected from one of the two runs"),
+        };
+
+        let config_a = run_a.config.config_for_block(name);
+        let config_b = run_b.config.config_for_block(name);
+        if config_a != config_b {
+            utils::info(&format!(
+                "~ block `{}` config changed: {} -> {}",
+                label,
+                config_a
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "<none>".to_string()),
+                config_b
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "<none>".to_string()),
+            ));
+        }
+
+        let (_, executions_a) = &run_a.traces[idx_a];
+        let (_, executions_b) = &run_b.traces[idx_b];
+
+        for input_idx in 0..executions_a.len().max(executions_b.len()) {
+            let (a, b) = match (executions_a.get(input_idx), executions_b.get(input_idx)) {
+                (Some(a), Some(b)) => (a, b),
+                (Some(_), None) => {
+                    utils::info(&format!(
+                        "- block `{}` input {} only in `{}`",
+                        label, input_idx, run_id_a
+                    ));
+                    continue;
+                }
+                (None, Some(_)) => {
+                    utils::info(&format!(
+                        "+ block `{}` input {} only in `{}`",
+                        label, input_idx, run_id_b
+                    ));
+                    continue;
+                }
+                (None, None) => continue,
+            };
+
+            for map_idx in 0..a.len().max(b.len()) {
+                let (ea, eb) = match (a.get(map_idx), b.get(map_idx)) {
+                    (Some(ea), Some(eb)) => (ea, eb),
+                    (Some(_), None) => {
+                        utils::info(&format!(
+                            "- block `{}`[{}][{}] only in `{}`",
+                            label, input_idx, map_idx, run_id_a
+                        ));
+                        continue;
+                    }
+                    (None, Some(_)) => {
+                        utils::info(&format!(
+                            "+ block `{}`[{}][{}] only in `{}`",
+                            label, input_idx, map_idx, run_id_b
+                        ));
+                        continue;
+                    }
+                    (None, None) => continue,
+                };
+
+                if ea.error != eb.error {
+                    utils::info(&format!(
+                        "~ block `{}`[{}][{}] error changed: {:?} -> {:?}",
+                        label, input_idx, map_idx, ea.error, eb.error
+                    ));
+                }
+
+                match (&ea.value, &eb.value) {
+                    (Some(va), Some(vb)) if va != vb => {
+                        let mut diffs = vec![];
+                        diff_values("", va, vb, &mut diffs);
+                        for (path, diff) in diffs {
+                            utils::info(&format!(
+                                "~ block `{}`[{}][{}] output{}: {:?}",
+                                label, input_idx, map_idx, path, diff
+                            ));
+                        }
+                    }
+                    (Some(_), None) => utils::info(&format!(
+                        "- block `{}`[{}][{}] output removed",
+                        label, input_idx, map_idx
+                    )),
+                    (None, Some(_)) => utils::info(&format!(
+                        "+ block `{}`[{}][{}] output added",
+                        label, input_idx, map_idx
+                    )),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_config_parses_legacy_unversioned_document() {
+        // Exactly what binaries prior to the versioning scheme wrote to
+        // `config.json`: a bare object, no `version`/`data` envelope.
+        let legacy = r#"{"start_time":1700000000,"app_hash":"abcdef","blocks":{"RETRIEVAL":{"k":8}}}"#;
+
+        let config = RunConfig::parse(legacy).unwrap();
+        assert_eq!(config.start_time, 1700000000);
+        assert_eq!(config.app_hash, "abcdef");
+        assert_eq!(
+            config.config_for_block("RETRIEVAL").unwrap(),
+            &serde_json::json!({"k": 8})
+        );
+    }
+
+    #[test]
+    fn run_config_parses_current_versioned_document() {
+        let current = write_versioned(
+            RunConfig::VERSION,
+            &RunConfig {
+                start_time: 1700000000,
+                app_hash: "abcdef".to_string(),
+                blocks: HashMap::new(),
+            },
+        )
+        .unwrap();
+
+        let config = RunConfig::parse(&current).unwrap();
+        assert_eq!(config.app_hash, "abcdef");
+    }
+
+    #[test]
+    fn run_config_rejects_document_from_a_future_version() {
+        let future = write_versioned(RunConfig::VERSION + 1, &serde_json::json!({})).unwrap();
+        assert!(RunConfig::parse(&future).is_err());
+    }
+
+    #[test]
+    fn load_versioned_recurses_from_an_enveloped_v0_document() {
+        // A document explicitly enveloped at version 0 (the bottom of the
+        // chain) must still migrate correctly to `RunConfig::VERSION`
+        // through the recursive `T::Previous` hop in `load_versioned`, the
+        // same hop `run_config_parses_legacy_unversioned_document` reaches
+        // from a bare, un-enveloped document.
+        let v0 = write_versioned(
+            RunConfigV0::VERSION,
+            &serde_json::json!({
+                "start_time": 1700000000u64,
+                "app_hash": "abcdef",
+                "blocks": {},
+            }),
+        )
+        .unwrap();
+
+        let config = RunConfig::parse(&v0).unwrap();
+        assert_eq!(config.app_hash, "abcdef");
+    }
+
+    #[test]
+    fn block_manifest_round_trips_names_with_delimiter_characters() {
+        // The whole point of `block.json` over the old `{idx}-{type}_{name}`
+        // directory name is that a name containing `-`/`_` no longer
+        // corrupts the encoding; a plain JSON round-trip is the proof.
+        let manifest = BlockManifest {
+            block_type: "my-type_with_underscores".to_string(),
+            name: "my_block-with-delimiters".to_string(),
+        };
+
+        let raw = serde_json::to_string(&manifest).unwrap();
+        let parsed: BlockManifest = serde_json::from_str(&raw).unwrap();
+        assert_eq!(parsed.block_type, manifest.block_type);
+        assert_eq!(parsed.name, manifest.name);
+    }
+
+    #[test]
+    fn parse_legacy_block_dir_name_reads_the_pre_manifest_format() {
+        let (block_idx, block_type, name) =
+            parse_legacy_block_dir_name("2-RETRIEVAL_search_docs").unwrap();
+        assert_eq!(block_idx, 2);
+        assert_eq!(block_type.to_string(), "RETRIEVAL");
+        assert_eq!(name, "search_docs");
+    }
+
+    #[test]
+    fn parse_legacy_block_dir_name_rejects_the_current_bare_index_format() {
+        assert!(parse_legacy_block_dir_name("2").is_err());
+    }
+
+    #[test]
+    fn run_filter_matches_is_conjunctive() {
+        let config = RunConfig {
+            start_time: 1700000000,
+            app_hash: "abcdef".to_string(),
+            blocks: HashMap::new(),
+        };
+
+        assert!(RunFilter::default().matches(&config));
+        assert!(RunFilter {
+            app_hash: Some("abcdef".to_string()),
+            since: Some(1600000000),
+            limit: None,
+        }
+        .matches(&config));
+        assert!(!RunFilter {
+            app_hash: Some("other".to_string()),
+            ..Default::default()
+        }
+        .matches(&config));
+        assert!(!RunFilter {
+            since: Some(1800000000),
+            ..Default::default()
+        }
+        .matches(&config));
+    }
+
+    #[test]
+    fn expectation_matches_distinguishes_missing_path_from_mismatch() {
+        let output = serde_json::json!({"answer": "42", "count": 3});
+
+        assert_eq!(
+            expectation_matches(&output, "/answer", "^\\d+$").unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            expectation_matches(&output, "/answer", "^[a-z]+$").unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            expectation_matches(&output, "/count", "^3$").unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            expectation_matches(&output, "/missing", ".*").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn diff_values_walks_objects_and_reports_leaf_changes() {
+        let a = serde_json::json!({"answer": "42", "removed": true, "nested": {"x": 1}});
+        let b = serde_json::json!({"answer": "43", "added": false, "nested": {"x": 1}});
+
+        let mut out = vec![];
+        diff_values("", &a, &b, &mut out);
+        out.sort_by(|x, y| x.0.cmp(&y.0));
+
+        let paths: Vec<&str> = out.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, vec!["/added", "/answer", "/removed"]);
+
+        assert!(matches!(
+            out.iter().find(|(p, _)| p == "/answer").unwrap().1,
+            ValueDiff::Changed { .. }
+        ));
+        assert!(matches!(
+            out.iter().find(|(p, _)| p == "/added").unwrap().1,
+            ValueDiff::Added(_)
+        ));
+        assert!(matches!(
+            out.iter().find(|(p, _)| p == "/removed").unwrap().1,
+            ValueDiff::Removed(_)
+        ));
+    }
+
+    fn unique_temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dust-run-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn run_paths_writable_and_locate_prefer_earlier_locations() {
+        async_std::task::block_on(async {
+            let base = unique_temp_dir("run_paths");
+            let _ = async_std::fs::remove_dir_all(&base).await;
+            let primary = base.join("primary");
+            let archive = base.join("archive");
+            async_std::fs::create_dir_all(archive.join("shared")).await.unwrap();
+            async_std::fs::create_dir_all(archive.join("archive-only")).await.unwrap();
+
+            let paths = RunPaths::from_locations(vec![primary.clone(), archive.clone()]);
+
+            // `primary` doesn't exist yet: `writable` falls through to
+            // creating the first location it can, rather than returning
+            // `archive` even though that one already exists.
+            assert_eq!(paths.writable().await.unwrap(), &primary);
+            assert!(primary.is_dir().await);
+
+            async_std::fs::create_dir_all(primary.join("shared")).await.unwrap();
+
+            // `shared` now exists in both; `locate` returns the
+            // earlier-priority location.
+            assert_eq!(paths.locate("shared").await, Some(&primary));
+            assert_eq!(paths.locate("archive-only").await, Some(&archive));
+            assert_eq!(paths.locate("missing").await, None);
+            assert_eq!(paths.readable().await, vec![&primary, &archive]);
+
+            async_std::fs::remove_dir_all(&base).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn filesystem_store_list_runs_annotated_merges_and_dedups_locations() {
+        async_std::task::block_on(async {
+            let base = unique_temp_dir("list_runs");
+            let _ = async_std::fs::remove_dir_all(&base).await;
+            let primary = base.join("primary");
+            let archive = base.join("archive");
+
+            let store = FilesystemStore::with_paths(RunPaths::from_locations(vec![
+                primary.clone(),
+                archive.clone(),
+            ]));
+
+            let shared_config = RunConfig {
+                start_time: 100,
+                app_hash: "primary-wins".to_string(),
+                blocks: HashMap::new(),
+            };
+            let archive_only_config = RunConfig {
+                start_time: 200,
+                app_hash: "archive-only".to_string(),
+                blocks: HashMap::new(),
+            };
+            // Same run_id in both locations, with a different `app_hash` so
+            // we can tell which one `list_runs_annotated` picked.
+            write_run_config(&primary, "shared", &shared_config).await;
+            write_run_config(&archive, "shared", &RunConfig {
+                app_hash: "archive-loses".to_string(),
+                ..shared_config.clone()
+            })
+            .await;
+            write_run_config(&archive, "archive-only", &archive_only_config).await;
+
+            let mut runs = store.list_runs_annotated(&RunFilter::default()).await.unwrap();
+            runs.sort_by(|a, b| a.0.cmp(&b.0));
+
+            assert_eq!(runs.len(), 2);
+            assert_eq!(runs[0].0, "archive-only");
+            assert_eq!(runs[0].2, archive);
+            assert_eq!(runs[1].0, "shared");
+            assert_eq!(runs[1].1.app_hash, "primary-wins");
+            assert_eq!(runs[1].2, primary);
+
+            async_std::fs::remove_dir_all(&base).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn filesystem_store_load_traces_skips_a_block_directory_missing_its_manifest() {
+        async_std::task::block_on(async {
+            let base = unique_temp_dir("load_traces");
+            let _ = async_std::fs::remove_dir_all(&base).await;
+            let location = base.join("primary");
+
+            let store = FilesystemStore::with_paths(RunPaths::from_locations(vec![location.clone()]));
+            let run_dir = location.join("run");
+            async_std::fs::create_dir_all(&run_dir).await.unwrap();
+
+            // A fully-persisted block.
+            let ok_dir = run_dir.join(block_dir_name(0));
+            async_std::fs::create_dir_all(&ok_dir).await.unwrap();
+            write_block_manifest(&ok_dir, &BlockType::from_str("RETRIEVAL").unwrap(), "ok")
+                .await
+                .unwrap();
+            async_std::fs::write(
+                ok_dir.join("0.json"),
+                write_versioned(<Vec<BlockExecution> as Migrate>::VERSION, &Vec::<BlockExecution>::new())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+            // A block directory with no block.json, as `run_persist_task`
+            // would leave behind if the process crashed between
+            // `create_dir_all` and `write_block_manifest`.
+            let crashed_dir = run_dir.join(block_dir_name(1));
+            async_std::fs::create_dir_all(&crashed_dir).await.unwrap();
+
+            let traces = store.load_traces("run").await.unwrap();
+
+            assert_eq!(traces.len(), 1);
+            assert_eq!(traces[0].0 .1, "ok");
+
+            async_std::fs::remove_dir_all(&base).await.unwrap();
+        });
+    }
+
+    async fn write_run_config(location: &Path, run_id: &str, config: &RunConfig) {
+        let run_dir = location.join(run_id);
+        async_std::fs::create_dir_all(&run_dir).await.unwrap();
+        async_std::fs::write(
+            run_dir.join("config.json"),
+            write_versioned(RunConfig::VERSION, config).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn record_first_error_keeps_the_earliest_failure() {
+        let mut first_err = None;
+        record_first_error(&mut first_err, Ok(()));
+        record_first_error(&mut first_err, Err(anyhow!("first")));
+        record_first_error(&mut first_err, Err(anyhow!("second")));
+
+        assert_eq!(first_err.unwrap().to_string(), "first");
+    }
+
+    #[test]
+    fn run_create_rejects_incremental_persistence_under_sql_store() {
+        async_std::task::block_on(async {
+            // `Run::create`'s streaming persistence has no `SqlStore`
+            // equivalent; it must fail loudly rather than silently write to
+            // a filesystem location `store()` will never read back from.
+            std::env::set_var("DUST_RUN_STORE_URL", "sqlite::memory:");
+            let result = Run::create(RunConfig {
+                start_time: 0,
+                app_hash: "test".to_string(),
+                blocks: HashMap::new(),
+            })
+            .await;
+            std::env::remove_var("DUST_RUN_STORE_URL");
+
+            assert!(result.is_err());
+        });
+    }
+
+    #[test]
+    fn sql_store_round_trips_a_run_and_filters_by_app_hash() {
+        async_std::task::block_on(async {
+            let store = SqlStore::init("sqlite::memory:").await.unwrap();
+
+            let run = Run::new(RunConfig {
+                start_time: 1700000000,
+                app_hash: "app-a".to_string(),
+                blocks: HashMap::new(),
+            });
+            store.store_run(&run).await.unwrap();
+
+            let loaded = store.load_config(run.run_id()).await.unwrap();
+            assert_eq!(loaded.app_hash, "app-a");
+
+            let matching = store
+                .list_runs(&RunFilter {
+                    app_hash: Some("app-a".to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(matching.len(), 1);
+
+            let non_matching = store
+                .list_runs(&RunFilter {
+                    app_hash: Some("app-b".to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert!(non_matching.is_empty());
+        });
+    }
+
+    #[test]
+    fn sql_store_is_visible_across_concurrent_connections() {
+        // A default `AnyPool` hands `sqlite::memory:` a private database per
+        // connection, so a write on one connection can be invisible to a
+        // read on another. Store and list from separate spawned tasks (each
+        // of which may borrow a different pool connection) to prove
+        // `SqlStore::init`'s single-connection pool keeps them on the same
+        // database.
+        async_std::task::block_on(async {
+            let store = std::sync::Arc::new(SqlStore::init("sqlite::memory:").await.unwrap());
+
+            let writer = {
+                let store = store.clone();
+                async_std::task::spawn(async move {
+                    let run = Run::new(RunConfig {
+                        start_time: 1700000000,
+                        app_hash: "app-a".to_string(),
+                        blocks: HashMap::new(),
+                    });
+                    store.store_run(&run).await.unwrap();
+                })
+            };
+            writer.await;
+
+            let reader = {
+                let store = store.clone();
+                async_std::task::spawn(async move {
+                    store.list_runs(&RunFilter::default()).await.unwrap()
+                })
+            };
+            let runs = reader.await;
+
+            assert_eq!(runs.len(), 1);
+        });
+    }
 }
\ No newline at end of file